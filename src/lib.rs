@@ -6,14 +6,185 @@ extern crate ndarray_parallel;
 extern crate numpy;
 extern crate pyo3;
 
-use ndarray::{Array1, Zip};
+use ndarray::{Array1, ArrayD, IxDyn, Zip};
 use ndarray_parallel::prelude::*;
 
-use numpy::{IntoPyArray, PyArrayDyn, PyArray1};
+use numpy::{IntoPyArray, PyArrayDyn, PyArray1, PyArray2};
+use pyo3::exceptions::ValueError;
 use pyo3::prelude::{pymodule, Py, PyModule, PyResult, Python};
+use pyo3::types::PyAny;
 
 use healpix::compass_point::{MainWind, Cardinal, Ordinal};
 
+/// Sentinel pixel index written out for NaN or out-of-domain inputs,
+/// mirroring astropy-healpix's `INVALID_INDEX`.
+const INVALID_INDEX: i64 = -1;
+
+/// Whether `(lon, lat)` is finite and `lat` falls within `[-pi/2, pi/2]`,
+/// i.e. whether it is safe to hash into a pixel index.
+fn is_valid_lonlat(lon: f64, lat: f64) -> bool {
+    lon.is_finite() && lat.is_finite() && lat >= -std::f64::consts::FRAC_PI_2 && lat <= std::f64::consts::FRAC_PI_2
+}
+
+/// `depth` may be passed either as a plain Python int (applied to every
+/// position) or as a Numpy array broadcast against `lon`/`lat`/`ipix`.
+fn depth_to_array(depth: &PyAny) -> PyResult<ArrayD<u8>> {
+    if let Ok(depth) = depth.extract::<u8>() {
+        return Ok(ArrayD::from_elem(IxDyn(&[]), depth));
+    }
+
+    let depth: &PyArrayDyn<u8> = depth.extract()?;
+    Ok(depth.as_array().to_owned())
+}
+
+/// Numpy-style broadcasting of two shapes: dimensions are aligned from
+/// the right and must either match or be `1`.
+fn broadcast_two(a: &[usize], b: &[usize]) -> PyResult<Vec<usize>> {
+    let n = a.len().max(b.len());
+    let mut shape = vec![1_usize; n];
+    for i in 0..n {
+        let ai = if i + a.len() < n { 1 } else { a[i + a.len() - n] };
+        let bi = if i + b.len() < n { 1 } else { b[i + b.len() - n] };
+        shape[i] = if ai == bi {
+            ai
+        } else if ai == 1 {
+            bi
+        } else if bi == 1 {
+            ai
+        } else {
+            return Err(ValueError::py_err(
+                "shape mismatch: objects cannot be broadcast to a single shape",
+            ));
+        };
+    }
+    Ok(shape)
+}
+
+/// Broadcast all the given shapes together, Numpy-style.
+fn broadcast_shapes(shapes: &[&[usize]]) -> PyResult<Vec<usize>> {
+    let mut result: Vec<usize> = vec![];
+    for shape in shapes {
+        result = broadcast_two(&result, shape)?;
+    }
+    Ok(result)
+}
+
+type Matrix3 = [[f64; 3]; 3];
+
+const IDENTITY3: Matrix3 = [
+    [1.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0],
+    [0.0, 0.0, 1.0],
+];
+
+/// Rotation matrix from the equatorial frame to the Galactic frame: the
+/// FK5 (J2000) values of SLALIB's `sla_EQGAL` (Murray 1989).
+const EQUATORIAL_TO_GALACTIC: Matrix3 = [
+    [-0.054875539726, -0.873437108010, -0.483834985808],
+    [ 0.494109453312, -0.444829589425,  0.746982251810],
+    [-0.867666135858, -0.198076386122,  0.455983795705],
+];
+
+/// Mean obliquity of the ecliptic at J2000.0, in degrees (IAU 1980).
+const OBLIQUITY_J2000_DEG: f64 = 23.4392911;
+
+/// Rotation matrix from the equatorial (ICRS) frame to the ecliptic
+/// frame: a rotation about the x axis by the obliquity of the ecliptic.
+fn equatorial_to_ecliptic() -> Matrix3 {
+    let eps = OBLIQUITY_J2000_DEG.to_radians();
+    let (s, c) = eps.sin_cos();
+    [
+        [1.0, 0.0, 0.0],
+        [0.0,  c,   s],
+        [0.0, -s,   c],
+    ]
+}
+
+fn transpose3(m: &Matrix3) -> Matrix3 {
+    let mut t = IDENTITY3;
+    for i in 0..3 {
+        for j in 0..3 {
+            t[i][j] = m[j][i];
+        }
+    }
+    t
+}
+
+fn matmul3(a: &Matrix3, b: &Matrix3) -> Matrix3 {
+    let mut out = IDENTITY3;
+    for i in 0..3 {
+        for j in 0..3 {
+            out[i][j] = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+fn matvec3(m: &Matrix3, v: [f64; 3]) -> [f64; 3] {
+    let mut out = [0.0; 3];
+    for i in 0..3 {
+        out[i] = m[i][0] * v[0] + m[i][1] * v[1] + m[i][2] * v[2];
+    }
+    out
+}
+
+/// Rotation matrix from the equatorial (ICRS) frame to the named
+/// `frame`, one of `"equatorial"`/`"icrs"`, `"galactic"` or `"ecliptic"`.
+fn equatorial_to_frame(frame: &str) -> PyResult<Matrix3> {
+    match frame {
+        "equatorial" | "icrs" => Ok(IDENTITY3),
+        "galactic" => Ok(EQUATORIAL_TO_GALACTIC),
+        "ecliptic" => Ok(equatorial_to_ecliptic()),
+        _ => Err(ValueError::py_err(format!(
+            "unknown frame {:?}, expected one of \"equatorial\", \"galactic\", \"ecliptic\"",
+            frame
+        ))),
+    }
+}
+
+/// Rotation matrix taking positions expressed in `from_frame` to
+/// positions expressed in `to_frame`.
+fn frame_rotation_matrix(from_frame: &str, to_frame: &str) -> PyResult<Matrix3> {
+    let eq_to_from = equatorial_to_frame(from_frame)?;
+    let eq_to_to = equatorial_to_frame(to_frame)?;
+    // from_frame -> equatorial -> to_frame
+    Ok(matmul3(&eq_to_to, &transpose3(&eq_to_from)))
+}
+
+/// Read a user-supplied `3x3` Numpy array into our plain `Matrix3`.
+fn pyarray2_to_matrix3(matrix: &PyArray2<f64>) -> PyResult<Matrix3> {
+    let matrix = matrix.as_array();
+    if matrix.shape() != [3, 3] {
+        return Err(ValueError::py_err("matrix must have shape (3, 3)"));
+    }
+
+    let mut m = IDENTITY3;
+    for i in 0..3 {
+        for j in 0..3 {
+            m[i][j] = matrix[[i, j]];
+        }
+    }
+    Ok(m)
+}
+
+fn lonlat_to_xyz(lon: f64, lat: f64) -> [f64; 3] {
+    let (sin_lon, cos_lon) = lon.sin_cos();
+    let (sin_lat, cos_lat) = lat.sin_cos();
+    [cos_lat * cos_lon, cos_lat * sin_lon, sin_lat]
+}
+
+fn xyz_to_lonlat(v: [f64; 3]) -> (f64, f64) {
+    let lon = v[1].atan2(v[0]);
+    let lat = v[2].atan2((v[0] * v[0] + v[1] * v[1]).sqrt());
+    (lon, lat)
+}
+
+/// Rotate a single `(lon, lat)` position on the unit sphere by `matrix`.
+fn rotate_lonlat_by_matrix(lon: f64, lat: f64, matrix: &Matrix3) -> (f64, f64) {
+    let v = lonlat_to_xyz(lon, lat);
+    xyz_to_lonlat(matvec3(matrix, v))
+}
+
 
 /// This uses rust-numpy for numpy interoperability between
 /// Python and Rust.
@@ -28,101 +199,168 @@ use healpix::compass_point::{MainWind, Cardinal, Ordinal};
 #[pymodule]
 fn cdshealpix(_py: Python, m: &PyModule) -> PyResult<()> {
     /// wrapper of `lonlat_to_healpix`
+    /// `depth` may be a scalar or an array broadcast against `lon`/`lat`;
+    /// the output is freshly allocated at the broadcast shape.
+    /// A non-finite `lon`/`lat` or a `lat` outside `[-pi/2, pi/2]` writes
+    /// the sentinel `INVALID_INDEX` (`-1`) instead of hashing garbage,
+    /// following astropy-healpix's convention.
     #[pyfn(m, "lonlat_to_healpix")]
-    fn lonlat_to_healpix(_py: Python,
-        depth: u8,
+    fn lonlat_to_healpix(py: Python,
+        depth: &PyAny,
         lon: &PyArrayDyn<f64>,
-        lat: &PyArrayDyn<f64>,
-        ipix: &PyArrayDyn<u64>)
-    -> PyResult<()> {
+        lat: &PyArrayDyn<f64>)
+    -> PyResult<Py<PyArrayDyn<i64>>> {
         let lon = lon.as_array();
         let lat = lat.as_array();
-        let mut ipix = ipix.as_array_mut();
-        
-        let layer = healpix::nested::get_or_create(depth);
+        let depth = depth_to_array(depth)?;
+
+        let shape = broadcast_shapes(&[lon.shape(), lat.shape(), depth.shape()])?;
+        let lon = lon.broadcast(shape.clone()).unwrap();
+        let lat = lat.broadcast(shape.clone()).unwrap();
+        let depth = depth.broadcast(shape.clone()).unwrap();
+
+        let mut ipix = ArrayD::<i64>::zeros(IxDyn(&shape));
         Zip::from(&mut ipix)
             .and(&lon)
             .and(&lat)
-            .par_apply(|p, &lon, &lat| {
-                *p = layer.hash(lon, lat);
+            .and(&depth)
+            .par_apply(|p, &lon, &lat, &depth| {
+                *p = if is_valid_lonlat(lon, lat) {
+                    let layer = healpix::nested::get_or_create(depth);
+                    layer.hash(lon, lat) as i64
+                } else {
+                    INVALID_INDEX
+                };
             });
 
-        Ok(())
+        Ok(ipix.into_pyarray(py).to_owned())
     }
 
     /// wrapper of `healpix_to_lonlat`
+    /// `depth` may be a scalar or an array broadcast against `ipix`; the
+    /// outputs are freshly allocated at the broadcast shape.
+    /// A sentinel `ipix` (`-1`) writes `NaN` to `lon`/`lat` instead of
+    /// indexing with a bogus pixel.
     #[pyfn(m, "healpix_to_lonlat")]
-    fn healpix_to_lonlat(_py: Python,
-        depth: u8,
-        ipix: &PyArrayDyn<u64>,
-        lon: &PyArrayDyn<f64>,
-        lat: &PyArrayDyn<f64>)
-    -> PyResult<()> {
-        let mut lon = lon.as_array_mut();
-        let mut lat = lat.as_array_mut();
+    fn healpix_to_lonlat(py: Python,
+        depth: &PyAny,
+        ipix: &PyArrayDyn<i64>)
+    -> PyResult<(Py<PyArrayDyn<f64>>, Py<PyArrayDyn<f64>>)> {
         let ipix = ipix.as_array();
-        
-        let layer = healpix::nested::get_or_create(depth);
-        Zip::from(&ipix)
-            .and(&mut lon)
+        let depth = depth_to_array(depth)?;
+
+        let shape = broadcast_shapes(&[ipix.shape(), depth.shape()])?;
+        let ipix = ipix.broadcast(shape.clone()).unwrap();
+        let depth = depth.broadcast(shape.clone()).unwrap();
+
+        let mut lon = ArrayD::<f64>::zeros(IxDyn(&shape));
+        let mut lat = ArrayD::<f64>::zeros(IxDyn(&shape));
+        Zip::from(&mut lon)
             .and(&mut lat)
-            .par_apply(|&p, lon, lat| {
-                let (l, b) = layer.center(p);
-                *lon = l;
-                *lat = b;
+            .and(&ipix)
+            .and(&depth)
+            .par_apply(|lon, lat, &p, &depth| {
+                if p == INVALID_INDEX {
+                    *lon = std::f64::NAN;
+                    *lat = std::f64::NAN;
+                } else {
+                    let layer = healpix::nested::get_or_create(depth);
+                    let (l, b) = layer.center(p as u64);
+                    *lon = l;
+                    *lat = b;
+                }
             });
 
-        Ok(())
+        Ok((lon.into_pyarray(py).to_owned(), lat.into_pyarray(py).to_owned()))
     }
 
     /// wrapper of `vertices`
+    /// `depth` may be a scalar or an array broadcast against `ipix`; the
+    /// outputs are freshly allocated at the broadcast shape with an extra
+    /// trailing axis of size 4 (`[S, E, N, W]`).
+    /// A sentinel `ipix` (`-1`) writes `NaN` to its 4 vertices instead of
+    /// indexing with a bogus pixel.
     #[pyfn(m, "vertices")]
-    fn vertices(_py: Python,
-        depth: u8,
-        ipix: &PyArrayDyn<u64>,
-        lon: &PyArrayDyn<f64>,
-        lat: &PyArrayDyn<f64>)
-    -> PyResult<()> {
+    fn vertices(py: Python,
+        depth: &PyAny,
+        ipix: &PyArrayDyn<i64>)
+    -> PyResult<(Py<PyArrayDyn<f64>>, Py<PyArrayDyn<f64>>)> {
         let ipix = ipix.as_array();
-        let mut lon = lon.as_array_mut();
-        let mut lat = lat.as_array_mut();
+        let depth = depth_to_array(depth)?;
+
+        let shape = broadcast_shapes(&[ipix.shape(), depth.shape()])?;
+        let ipix = ipix.broadcast(shape.clone()).unwrap();
+        let depth = depth.broadcast(shape.clone()).unwrap();
+
+        let mut out_shape = shape.clone();
+        out_shape.push(4);
+        let mut lon = ArrayD::<f64>::zeros(IxDyn(&out_shape));
+        let mut lat = ArrayD::<f64>::zeros(IxDyn(&out_shape));
 
         Zip::from(lon.genrows_mut())
             .and(lat.genrows_mut())
             .and(&ipix)
-            .par_apply(|mut lon, mut lat, &p| {
-                let [(s_lon, s_lat), (e_lon, e_lat), (n_lon, n_lat), (w_lon, w_lat)] = healpix::nested::vertices(depth, p);
+            .and(&depth)
+            .par_apply(|mut lon, mut lat, &p, &depth| {
+                if p == INVALID_INDEX {
+                    for k in 0..4 {
+                        lon[k] = std::f64::NAN;
+                        lat[k] = std::f64::NAN;
+                    }
+                    return;
+                }
+
+                let [(s_lon, s_lat), (e_lon, e_lat), (n_lon, n_lat), (w_lon, w_lat)] = healpix::nested::vertices(depth, p as u64);
                 lon[0] = s_lon;
                 lat[0] = s_lat;
-                
+
                 lon[1] = e_lon;
                 lat[1] = e_lat;
-                
+
                 lon[2] = n_lon;
                 lat[2] = n_lat;
-                
+
                 lon[3] = w_lon;
                 lat[3] = w_lat;
             });
 
-        Ok(())
+        Ok((lon.into_pyarray(py).to_owned(), lat.into_pyarray(py).to_owned()))
     }
 
     /// Wrapper of `neighbours`
-    /// The given array must be of size 9
-    /// `[S, SE, E, SW, C, NE, W, NW, N]`
+    /// `depth` may be a scalar or an array broadcast against `ipix`; the
+    /// output is freshly allocated at the broadcast shape with an extra
+    /// trailing axis of size 9, `[S, SE, E, SW, C, NE, W, NW, N]`.
+    /// A sentinel `ipix` (`-1`) writes `-1` to all 9 neighbours instead of
+    /// indexing with a bogus pixel.
     #[pyfn(m, "neighbours")]
-    fn neighbours(_py: Python,
-        depth: u8,
-        ipix: &PyArrayDyn<u64>,
-        neighbours: &PyArrayDyn<i64>)
-    -> PyResult<()> {
+    fn neighbours(py: Python,
+        depth: &PyAny,
+        ipix: &PyArrayDyn<i64>)
+    -> PyResult<Py<PyArrayDyn<i64>>> {
         let ipix = ipix.as_array();
-        let mut neighbours = neighbours.as_array_mut();
+        let depth = depth_to_array(depth)?;
+
+        let shape = broadcast_shapes(&[ipix.shape(), depth.shape()])?;
+        let ipix = ipix.broadcast(shape.clone()).unwrap();
+        let depth = depth.broadcast(shape.clone()).unwrap();
+
+        let mut out_shape = shape.clone();
+        out_shape.push(9);
+        let mut neighbours = ArrayD::<i64>::zeros(IxDyn(&out_shape));
 
         Zip::from(neighbours.genrows_mut())
             .and(&ipix)
-            .par_apply(|mut n, &p| {
+            .and(&depth)
+            .par_apply(|mut n, &p, &depth| {
+                if p == INVALID_INDEX {
+                    for k in 0..9 {
+                        n[k] = INVALID_INDEX;
+                    }
+                    return;
+                }
+
+                let p = p as u64;
                 let map = healpix::nested::neighbours(depth, p, true);
 
                 n[0] = to_ref_i64(map.get(MainWind::S));
@@ -136,7 +374,323 @@ fn cdshealpix(_py: Python, m: &PyModule) -> PyResult<()> {
                 n[8] = to_ref_i64(map.get(MainWind::N));
             });
 
-        Ok(())
+        Ok(neighbours.into_pyarray(py).to_owned())
+    }
+
+    /// wrapper of `lonlat_to_healpix_ring`
+    /// `depth` may be a scalar or an array broadcast against `lon`/`lat`;
+    /// the output is freshly allocated at the broadcast shape.
+    /// A non-finite `lon`/`lat` or a `lat` outside `[-pi/2, pi/2]` writes
+    /// the sentinel `INVALID_INDEX` (`-1`), mirroring `lonlat_to_healpix`.
+    #[pyfn(m, "lonlat_to_healpix_ring")]
+    fn lonlat_to_healpix_ring(py: Python,
+        depth: &PyAny,
+        lon: &PyArrayDyn<f64>,
+        lat: &PyArrayDyn<f64>)
+    -> PyResult<Py<PyArrayDyn<i64>>> {
+        let lon = lon.as_array();
+        let lat = lat.as_array();
+        let depth = depth_to_array(depth)?;
+
+        let shape = broadcast_shapes(&[lon.shape(), lat.shape(), depth.shape()])?;
+        let lon = lon.broadcast(shape.clone()).unwrap();
+        let lat = lat.broadcast(shape.clone()).unwrap();
+        let depth = depth.broadcast(shape.clone()).unwrap();
+
+        let mut ipix = ArrayD::<i64>::zeros(IxDyn(&shape));
+        Zip::from(&mut ipix)
+            .and(&lon)
+            .and(&lat)
+            .and(&depth)
+            .par_apply(|p, &lon, &lat, &depth| {
+                *p = if is_valid_lonlat(lon, lat) {
+                    let nside = 1_u64 << (depth as u32);
+                    let layer = healpix::nested::get_or_create(depth);
+                    let ipix_nested = layer.hash(lon, lat);
+                    nest2ring(nside, ipix_nested) as i64
+                } else {
+                    INVALID_INDEX
+                };
+            });
+
+        Ok(ipix.into_pyarray(py).to_owned())
+    }
+
+    /// wrapper of `healpix_ring_to_lonlat`
+    /// `depth` may be a scalar or an array broadcast against `ipix`; the
+    /// outputs are freshly allocated at the broadcast shape.
+    /// A sentinel `ipix` (`-1`) writes `NaN` to `lon`/`lat` instead of
+    /// indexing with a bogus pixel.
+    #[pyfn(m, "healpix_ring_to_lonlat")]
+    fn healpix_ring_to_lonlat(py: Python,
+        depth: &PyAny,
+        ipix: &PyArrayDyn<i64>)
+    -> PyResult<(Py<PyArrayDyn<f64>>, Py<PyArrayDyn<f64>>)> {
+        let ipix = ipix.as_array();
+        let depth = depth_to_array(depth)?;
+
+        let shape = broadcast_shapes(&[ipix.shape(), depth.shape()])?;
+        let ipix = ipix.broadcast(shape.clone()).unwrap();
+        let depth = depth.broadcast(shape.clone()).unwrap();
+
+        let mut lon = ArrayD::<f64>::zeros(IxDyn(&shape));
+        let mut lat = ArrayD::<f64>::zeros(IxDyn(&shape));
+        Zip::from(&mut lon)
+            .and(&mut lat)
+            .and(&ipix)
+            .and(&depth)
+            .par_apply(|lon, lat, &p, &depth| {
+                if p == INVALID_INDEX {
+                    *lon = std::f64::NAN;
+                    *lat = std::f64::NAN;
+                } else {
+                    let nside = 1_u64 << (depth as u32);
+                    let layer = healpix::nested::get_or_create(depth);
+                    let ipix_nested = ring2nest(nside, p as u64);
+                    let (l, b) = layer.center(ipix_nested);
+                    *lon = l;
+                    *lat = b;
+                }
+            });
+
+        Ok((lon.into_pyarray(py).to_owned(), lat.into_pyarray(py).to_owned()))
+    }
+
+    /// wrapper of `vertices_ring`
+    /// `depth` may be a scalar or an array broadcast against `ipix`; the
+    /// outputs are freshly allocated at the broadcast shape with an extra
+    /// trailing axis of size 4 (`[S, E, N, W]`).
+    /// A sentinel `ipix` (`-1`) writes `NaN` to its 4 vertices instead of
+    /// indexing with a bogus pixel.
+    #[pyfn(m, "vertices_ring")]
+    fn vertices_ring(py: Python,
+        depth: &PyAny,
+        ipix: &PyArrayDyn<i64>)
+    -> PyResult<(Py<PyArrayDyn<f64>>, Py<PyArrayDyn<f64>>)> {
+        let ipix = ipix.as_array();
+        let depth = depth_to_array(depth)?;
+
+        let shape = broadcast_shapes(&[ipix.shape(), depth.shape()])?;
+        let ipix = ipix.broadcast(shape.clone()).unwrap();
+        let depth = depth.broadcast(shape.clone()).unwrap();
+
+        let mut out_shape = shape.clone();
+        out_shape.push(4);
+        let mut lon = ArrayD::<f64>::zeros(IxDyn(&out_shape));
+        let mut lat = ArrayD::<f64>::zeros(IxDyn(&out_shape));
+
+        Zip::from(lon.genrows_mut())
+            .and(lat.genrows_mut())
+            .and(&ipix)
+            .and(&depth)
+            .par_apply(|mut lon, mut lat, &p, &depth| {
+                if p == INVALID_INDEX {
+                    for k in 0..4 {
+                        lon[k] = std::f64::NAN;
+                        lat[k] = std::f64::NAN;
+                    }
+                    return;
+                }
+
+                let nside = 1_u64 << (depth as u32);
+                let ipix_nested = ring2nest(nside, p as u64);
+                let [(s_lon, s_lat), (e_lon, e_lat), (n_lon, n_lat), (w_lon, w_lat)] = healpix::nested::vertices(depth, ipix_nested);
+                lon[0] = s_lon;
+                lat[0] = s_lat;
+
+                lon[1] = e_lon;
+                lat[1] = e_lat;
+
+                lon[2] = n_lon;
+                lat[2] = n_lat;
+
+                lon[3] = w_lon;
+                lat[3] = w_lat;
+            });
+
+        Ok((lon.into_pyarray(py).to_owned(), lat.into_pyarray(py).to_owned()))
+    }
+
+    /// Index-conversion ufunc, nested scheme -> ring scheme.
+    /// Pure bijection between the two pixel numbering schemes at a
+    /// given depth, so it does not need a `layer` lookup.
+    /// `depth` may be a scalar or an array broadcast against
+    /// `ipix_nested`; the output is freshly allocated at the broadcast
+    /// shape. A sentinel `ipix_nested` (`-1`) passes through unchanged.
+    #[pyfn(m, "nested_to_ring")]
+    fn nested_to_ring(py: Python,
+        depth: &PyAny,
+        ipix_nested: &PyArrayDyn<i64>)
+    -> PyResult<Py<PyArrayDyn<i64>>> {
+        let ipix_nested = ipix_nested.as_array();
+        let depth = depth_to_array(depth)?;
+
+        let shape = broadcast_shapes(&[ipix_nested.shape(), depth.shape()])?;
+        let ipix_nested = ipix_nested.broadcast(shape.clone()).unwrap();
+        let depth = depth.broadcast(shape.clone()).unwrap();
+
+        let mut ipix_ring = ArrayD::<i64>::zeros(IxDyn(&shape));
+        Zip::from(&mut ipix_ring)
+            .and(&ipix_nested)
+            .and(&depth)
+            .par_apply(|r, &n, &depth| {
+                *r = if n == INVALID_INDEX {
+                    INVALID_INDEX
+                } else {
+                    let nside = 1_u64 << (depth as u32);
+                    nest2ring(nside, n as u64) as i64
+                };
+            });
+
+        Ok(ipix_ring.into_pyarray(py).to_owned())
+    }
+
+    /// Index-conversion ufunc, ring scheme -> nested scheme.
+    /// `depth` may be a scalar or an array broadcast against
+    /// `ipix_ring`; the output is freshly allocated at the broadcast
+    /// shape. A sentinel `ipix_ring` (`-1`) passes through unchanged.
+    #[pyfn(m, "ring_to_nested")]
+    fn ring_to_nested(py: Python,
+        depth: &PyAny,
+        ipix_ring: &PyArrayDyn<i64>)
+    -> PyResult<Py<PyArrayDyn<i64>>> {
+        let ipix_ring = ipix_ring.as_array();
+        let depth = depth_to_array(depth)?;
+
+        let shape = broadcast_shapes(&[ipix_ring.shape(), depth.shape()])?;
+        let ipix_ring = ipix_ring.broadcast(shape.clone()).unwrap();
+        let depth = depth.broadcast(shape.clone()).unwrap();
+
+        let mut ipix_nested = ArrayD::<i64>::zeros(IxDyn(&shape));
+        Zip::from(&mut ipix_nested)
+            .and(&ipix_ring)
+            .and(&depth)
+            .par_apply(|n, &r, &depth| {
+                *n = if r == INVALID_INDEX {
+                    INVALID_INDEX
+                } else {
+                    let nside = 1_u64 << (depth as u32);
+                    ring2nest(nside, r as u64) as i64
+                };
+            });
+
+        Ok(ipix_nested.into_pyarray(py).to_owned())
+    }
+
+    /// Bilinear interpolation of a HEALPix map at arbitrary sky positions.
+    /// For each `(lon, lat)` returns the 4 nested pixel indices bracketing
+    /// it and the 4 matching weights, which always sum to 1.
+    /// A non-finite `lon`/`lat` or a `lat` outside `[-pi/2, pi/2]` writes
+    /// the `INVALID_INDEX` sentinel to all 4 pixels and `NaN` to all 4
+    /// weights, mirroring `lonlat_to_healpix`.
+    #[pyfn(m, "bilinear_interpolation")]
+    fn bilinear_interpolation(py: Python,
+        depth: u8,
+        lon: &PyArrayDyn<f64>,
+        lat: &PyArrayDyn<f64>)
+    -> (Py<PyArrayDyn<i64>>, Py<PyArrayDyn<f64>>) {
+        let lon = lon.as_array();
+        let lat = lat.as_array();
+
+        let nside = 1_u64 << (depth as u32);
+
+        let mut shape = lon.shape().to_vec();
+        shape.push(4);
+        let mut ipix = ArrayD::<i64>::zeros(IxDyn(&shape));
+        let mut weights = ArrayD::<f64>::zeros(IxDyn(&shape));
+
+        Zip::from(ipix.genrows_mut())
+            .and(weights.genrows_mut())
+            .and(&lon)
+            .and(&lat)
+            .par_apply(|mut p, mut w, &lon, &lat| {
+                if is_valid_lonlat(lon, lat) {
+                    let (p4, w4) = bilinear_weights(nside, lon, lat);
+                    for k in 0..4 {
+                        p[k] = p4[k] as i64;
+                        w[k] = w4[k];
+                    }
+                } else {
+                    for k in 0..4 {
+                        p[k] = INVALID_INDEX;
+                        w[k] = std::f64::NAN;
+                    }
+                }
+            });
+
+        (ipix.into_pyarray(py).to_owned(), weights.into_pyarray(py).to_owned())
+    }
+
+    /// Rotate `(lon, lat)` positions on the unit sphere by an arbitrary
+    /// `3x3` rotation `matrix` supplied by the caller. The named frame
+    /// presets are not returned as matrices; `lonlat_to_healpix_frame`
+    /// applies them by frame name.
+    #[pyfn(m, "rotate_lonlat")]
+    fn rotate_lonlat(py: Python,
+        lon: &PyArrayDyn<f64>,
+        lat: &PyArrayDyn<f64>,
+        matrix: &PyArray2<f64>)
+    -> PyResult<(Py<PyArrayDyn<f64>>, Py<PyArrayDyn<f64>>)> {
+        let lon = lon.as_array();
+        let lat = lat.as_array();
+        let matrix = pyarray2_to_matrix3(matrix)?;
+
+        let shape = lon.shape().to_vec();
+        let mut lon_out = ArrayD::<f64>::zeros(IxDyn(&shape));
+        let mut lat_out = ArrayD::<f64>::zeros(IxDyn(&shape));
+
+        Zip::from(&mut lon_out)
+            .and(&mut lat_out)
+            .and(&lon)
+            .and(&lat)
+            .par_apply(|lon_out, lat_out, &lon, &lat| {
+                let (l, b) = rotate_lonlat_by_matrix(lon, lat, &matrix);
+                *lon_out = l;
+                *lat_out = b;
+            });
+
+        Ok((lon_out.into_pyarray(py).to_owned(), lat_out.into_pyarray(py).to_owned()))
+    }
+
+    /// Hash `(lon, lat)` positions given in `from_frame` into a HEALPix
+    /// grid defined in `to_frame`. Both frames must be one of
+    /// `"equatorial"` (alias `"icrs"`), `"galactic"` or `"ecliptic"`.
+    /// `depth` may be a scalar or an array broadcast against `lon`/`lat`,
+    /// mirroring `lonlat_to_healpix`.
+    #[pyfn(m, "lonlat_to_healpix_frame")]
+    fn lonlat_to_healpix_frame(py: Python,
+        depth: &PyAny,
+        lon: &PyArrayDyn<f64>,
+        lat: &PyArrayDyn<f64>,
+        from_frame: &str,
+        to_frame: &str)
+    -> PyResult<Py<PyArrayDyn<i64>>> {
+        let lon = lon.as_array();
+        let lat = lat.as_array();
+        let depth = depth_to_array(depth)?;
+        let matrix = frame_rotation_matrix(from_frame, to_frame)?;
+
+        let shape = broadcast_shapes(&[lon.shape(), lat.shape(), depth.shape()])?;
+        let lon = lon.broadcast(shape.clone()).unwrap();
+        let lat = lat.broadcast(shape.clone()).unwrap();
+        let depth = depth.broadcast(shape.clone()).unwrap();
+
+        let mut ipix = ArrayD::<i64>::zeros(IxDyn(&shape));
+        Zip::from(&mut ipix)
+            .and(&lon)
+            .and(&lat)
+            .and(&depth)
+            .par_apply(|p, &lon, &lat, &depth| {
+                let (lon, lat) = rotate_lonlat_by_matrix(lon, lat, &matrix);
+                *p = if is_valid_lonlat(lon, lat) {
+                    let layer = healpix::nested::get_or_create(depth);
+                    layer.hash(lon, lat) as i64
+                } else {
+                    INVALID_INDEX
+                };
+            });
+
+        Ok(ipix.into_pyarray(py).to_owned())
     }
 
     /// Cone search
@@ -306,6 +860,260 @@ fn cdshealpix(_py: Python, m: &PyModule) -> PyResult<()> {
     Ok(())
 }
 
+// Base-cell layout of the HEALPix projection: for each of the 12 base
+// pixels, its row (`jrll`, counted from the North pole) and its column
+// (`jpll`) in the diamond pattern described in Gorski et al. (2005).
+const JRLL: [u64; 12] = [2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4];
+const JPLL: [u64; 12] = [1, 3, 5, 7, 0, 2, 4, 6, 1, 3, 5, 7];
+
+/// Deinterleave the even bits of `v`, i.e. the inverse of `spread_bits`.
+fn compress_bits(v: u64) -> u32 {
+    let mut v = v & 0x5555555555555555;
+    v = (v | (v >> 1)) & 0x3333333333333333;
+    v = (v | (v >> 2)) & 0x0f0f0f0f0f0f0f0f;
+    v = (v | (v >> 4)) & 0x00ff00ff00ff00ff;
+    v = (v | (v >> 8)) & 0x0000ffff0000ffff;
+    v = (v | (v >> 16)) & 0x00000000ffffffff;
+    v as u32
+}
+
+/// Interleave the bits of `v` with zeros, i.e. the inverse of `compress_bits`.
+fn spread_bits(v: u32) -> u64 {
+    let mut v = v as u64;
+    v = (v | (v << 16)) & 0x0000ffff0000ffff;
+    v = (v | (v << 8)) & 0x00ff00ff00ff00ff;
+    v = (v | (v << 4)) & 0x0f0f0f0f0f0f0f0f;
+    v = (v | (v << 2)) & 0x3333333333333333;
+    v = (v | (v << 1)) & 0x5555555555555555;
+    v
+}
+
+/// Split a nested pixel index into its base cell and its `(x, y)`
+/// coordinates within that base cell, following astropy-healpix's
+/// `xy_to_order`/`order_to_xy` bridge.
+fn nest2xyf(nside: u64, ipix_nested: u64) -> (u64, u32, u32) {
+    let npface = nside * nside;
+    let face_num = ipix_nested / npface;
+    let local = ipix_nested % npface;
+    let ix = compress_bits(local);
+    let iy = compress_bits(local >> 1);
+    (face_num, ix, iy)
+}
+
+/// Inverse of `nest2xyf`.
+fn xyf2nest(nside: u64, face_num: u64, ix: u32, iy: u32) -> u64 {
+    face_num * nside * nside + spread_bits(ix) + (spread_bits(iy) << 1)
+}
+
+/// `(x, y)` coordinates within a base cell, plus the base cell number,
+/// to a ring-scheme pixel index.
+fn xyf2ring(nside: u64, face_num: u64, ix: u32, iy: u32) -> u64 {
+    let nl4 = 4 * nside;
+    let ncap = 2 * nside * (nside - 1);
+    let npix = 12 * nside * nside;
+    let (ix, iy) = (ix as i64, iy as i64);
+
+    let jr = (JRLL[face_num as usize] * nside) as i64 - ix - iy - 1;
+
+    let (nr, n_before, kshift) = if jr < nside as i64 {
+        let nr = jr;
+        (nr, 2 * nr * (nr - 1), 0)
+    } else if jr > 3 * nside as i64 {
+        let nr = nl4 as i64 - jr;
+        (nr, npix as i64 - 2 * (nr + 1) * nr, 0)
+    } else {
+        let nr = nside as i64;
+        let kshift = (jr - nside as i64) & 1;
+        (nr, ncap as i64 + (jr - nside as i64) * nl4 as i64, kshift)
+    };
+
+    let mut jp = (JPLL[face_num as usize] as i64 * nr + ix - iy + 1 + kshift) / 2;
+    if jp > nl4 as i64 {
+        jp -= nl4 as i64;
+    } else if jp < 1 {
+        jp += nl4 as i64;
+    }
+
+    (n_before + jp - 1) as u64
+}
+
+/// Inverse of `xyf2ring`.
+fn ring2xyf(nside: u64, ipix_ring: u64) -> (u64, u32, u32) {
+    let ncap = 2 * nside * (nside - 1);
+    let npix = 12 * nside * nside;
+    let nl2 = 2 * nside;
+    let nl4 = 4 * nside;
+
+    let (iring, iphi, face_num, kshift, nr);
+    if ipix_ring < ncap {
+        // North polar cap
+        iring = ((1.0 + ((1 + 2 * ipix_ring) as f64).sqrt()) / 2.0) as u64;
+        iphi = ipix_ring + 1 - 2 * iring * (iring - 1);
+        kshift = 0;
+        nr = iring;
+        face_num = (iphi - 1) / nr;
+    } else if ipix_ring < npix - ncap {
+        // Equatorial belt
+        let ip = ipix_ring - ncap;
+        iring = ip / nl4 + nside;
+        iphi = ip % nl4 + 1;
+        kshift = ((iring + nside) & 1) as u32;
+        nr = nside;
+        let ire = iring - nside + 1;
+        let irm = nl2 + 2 - ire;
+        let ifm = (iphi + nside - 1 - ire / 2) / nside;
+        let ifp = (iphi + nside - 1 - irm / 2) / nside;
+        face_num = if ifp == ifm {
+            ifp | 4
+        } else if ifp < ifm {
+            ifp
+        } else {
+            ifm + 8
+        };
+    } else {
+        // South polar cap
+        let ip = npix - ipix_ring;
+        let iring_from_pole = ((1.0 + ((2 * ip - 1) as f64).sqrt()) / 2.0) as u64;
+        iphi = 4 * iring_from_pole + 1 - (ip - 2 * iring_from_pole * (iring_from_pole - 1));
+        kshift = 0;
+        nr = iring_from_pole;
+        iring = nl4 - iring_from_pole;
+        face_num = 8 + (iphi - 1) / nr;
+    }
+
+    let irt = iring as i64 - JRLL[face_num as usize] as i64 * nside as i64 + 1;
+    let ipt = 2 * iphi as i64 - JPLL[face_num as usize] as i64 * nr as i64 - kshift as i64 - 1;
+    let ipt = if ipt >= nl4 as i64 { ipt - 2 * nl4 as i64 } else { ipt };
+
+    let ix = ((ipt - irt) / 2) as u32;
+    let iy = ((-(ipt + irt)) / 2) as u32;
+
+    (face_num, ix, iy)
+}
+
+/// Convert a nested-scheme pixel index into its ring-scheme equivalent.
+fn nest2ring(nside: u64, ipix_nested: u64) -> u64 {
+    let (face_num, ix, iy) = nest2xyf(nside, ipix_nested);
+    xyf2ring(nside, face_num, ix, iy)
+}
+
+/// Convert a ring-scheme pixel index into its nested-scheme equivalent.
+fn ring2nest(nside: u64, ipix_ring: u64) -> u64 {
+    let (face_num, ix, iy) = ring2xyf(nside, ipix_ring);
+    xyf2nest(nside, face_num, ix, iy)
+}
+
+/// For ring `iring` (1-indexed from the North pole, `1..=4*nside-1`),
+/// the colatitude cosine `z` of its pixel centres, the number of pixels
+/// it holds, and the phase offset (`0.0` or `0.5` of a pixel) of its
+/// first pixel centre, following Gorski et al. (2005) eq. 4-6.
+fn ring_z_n_shift(nside: u64, iring: u64) -> (f64, u64, f64) {
+    let nside_f = nside as f64;
+    if iring < nside {
+        let i = iring as f64;
+        (1.0 - (i * i) / (3.0 * nside_f * nside_f), 4 * iring, 0.5)
+    } else if iring <= 3 * nside {
+        let i = iring as f64;
+        let shift = if (iring - nside) % 2 == 0 { 0.5 } else { 0.0 };
+        ((4.0 - 2.0 * i / nside_f) / 3.0, 4 * nside, shift)
+    } else {
+        let i2 = 4 * nside - iring;
+        let i = i2 as f64;
+        (-(1.0 - (i * i) / (3.0 * nside_f * nside_f)), 4 * i2, 0.5)
+    }
+}
+
+/// Number of ring-scheme pixels belonging to rings `1..iring` (exclusive),
+/// i.e. the ring-scheme index of the first pixel of ring `iring`.
+fn ring_n_before(nside: u64, iring: u64) -> u64 {
+    let ncap = 2 * nside * (nside - 1);
+    let npix = 12 * nside * nside;
+    if iring < nside {
+        2 * iring * (iring - 1)
+    } else if iring <= 3 * nside {
+        ncap + (iring - nside) * 4 * nside
+    } else {
+        let i2 = 4 * nside - iring;
+        npix - 2 * (i2 + 1) * i2
+    }
+}
+
+/// The ring-scheme global index of the `j`-th (0-indexed) pixel of ring `iring`.
+fn ring_pix(nside: u64, iring: u64, j: u64) -> u64 {
+    ring_n_before(nside, iring) + j
+}
+
+/// Ring number (`1..=4*nside-1`) of the ring whose pixel centres lie just
+/// North of (i.e. at a `z` greater than) the given `z`, clamped to a
+/// valid ring index.
+fn ring_above(nside: u64, z: f64) -> u64 {
+    let nside_f = nside as f64;
+    let az = z.abs();
+    let iring = if az <= 2.0 / 3.0 {
+        (nside_f * (2.0 - 1.5 * z)).floor() as i64
+    } else {
+        let tmp = (nside_f * (3.0 * (1.0 - az)).sqrt()).floor() as i64;
+        if z > 0.0 { tmp } else { 4 * nside as i64 - tmp - 1 }
+    };
+    iring.max(1).min(4 * nside as i64 - 1) as u64
+}
+
+/// For a ring holding `n` pixels with phase `shift`, find the two pixels
+/// bracketing `phi` and the weight (in `[0, 1]`) of the second (Eastward)
+/// one; wraps `phi` across the `2*pi` seam.
+fn bracket_in_ring(n: u64, shift: f64, phi: f64) -> (u64, u64, f64) {
+    use std::f64::consts::PI;
+    let n_f = n as f64;
+    let tmp = phi * n_f / (2.0 * PI) - shift;
+    let j0f = tmp.floor();
+    let w = tmp - j0f;
+    let j0 = (((j0f as i64) % n as i64 + n as i64) % n as i64) as u64;
+    let j1 = (j0 + 1) % n;
+    (j0, j1, w)
+}
+
+/// 4 nested-scheme pixels bracketing `(lon, lat)` and their bilinear
+/// weights (always summing to 1), as described by Gorski et al. (2005).
+fn bilinear_weights(nside: u64, lon: f64, lat: f64) -> ([u64; 4], [f64; 4]) {
+    let z = lat.sin();
+    let phi = lon;
+
+    let ir1 = ring_above(nside, z);
+    let ir2 = (ir1 + 1).min(4 * nside - 1);
+
+    let (z1, n1, shift1) = ring_z_n_shift(nside, ir1);
+    let (z2, n2, shift2) = ring_z_n_shift(nside, ir2);
+
+    let w_theta = if (z1 - z2).abs() > 0.0 {
+        ((z1 - z) / (z1 - z2)).max(0.0).min(1.0)
+    } else {
+        0.0
+    };
+
+    let (j1a, j1b, w_phi1) = bracket_in_ring(n1, shift1, phi);
+    let (j2a, j2b, w_phi2) = bracket_in_ring(n2, shift2, phi);
+
+    let pix_ring = [
+        ring_pix(nside, ir1, j1a),
+        ring_pix(nside, ir1, j1b),
+        ring_pix(nside, ir2, j2a),
+        ring_pix(nside, ir2, j2b),
+    ];
+    let weights = [
+        (1.0 - w_theta) * (1.0 - w_phi1),
+        (1.0 - w_theta) * w_phi1,
+        w_theta * (1.0 - w_phi2),
+        w_theta * w_phi2,
+    ];
+
+    let mut pix = [0_u64; 4];
+    for k in 0..4 {
+        pix[k] = ring2nest(nside, pix_ring[k]);
+    }
+
+    (pix, weights)
+}
+
 fn to_i64(val: Option<u64>) -> i64 {
     match val {
         Some(val) => val as i64,
@@ -357,3 +1165,80 @@ fn get_flat_cells(bmoc: healpix::nested::bmoc::BMOC) -> (Array1<u64>, Array1<u8>
 
     (ipix.into(), depth.into(), fully_covered.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_nest_roundtrip() {
+        for depth in 0..=4u8 {
+            let nside = 1_u64 << depth as u32;
+            let npix = 12 * nside * nside;
+            for ipix_nested in 0..npix {
+                let ipix_ring = nest2ring(nside, ipix_nested);
+                assert_eq!(
+                    ring2nest(nside, ipix_ring), ipix_nested,
+                    "nside={} ipix_nested={} ipix_ring={}", nside, ipix_nested, ipix_ring
+                );
+            }
+            for ipix_ring in 0..npix {
+                let ipix_nested = ring2nest(nside, ipix_ring);
+                assert_eq!(
+                    nest2ring(nside, ipix_nested), ipix_ring,
+                    "nside={} ipix_ring={} ipix_nested={}", nside, ipix_ring, ipix_nested
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bilinear_weights_at_pixel_centre() {
+        let depth = 2;
+        let nside = 1_u64 << depth as u32;
+        let layer = healpix::nested::get_or_create(depth);
+        for ipix in 0..(12 * nside * nside) {
+            let (lon, lat) = layer.center(ipix);
+            let (pix, weights) = bilinear_weights(nside, lon, lat);
+            let hit = pix.iter().position(|&p| p == ipix)
+                .unwrap_or_else(|| panic!("pixel {} not among its own bracketing pixels {:?}", ipix, pix));
+            assert!(
+                (weights[hit] - 1.0).abs() < 1e-6,
+                "expected weight ~1 at the centre of pixel {}, got {}", ipix, weights[hit]
+            );
+        }
+    }
+
+    #[test]
+    fn ring2xyf_does_not_underflow_in_equatorial_belt() {
+        // iphi can be as small as 1 while ire/2 or irm/2 equals nside, so a
+        // naive `iphi - ire / 2` panics under debug overflow checks. Exercise
+        // every ring of the equatorial belt to make sure it no longer does.
+        for depth in 1..=5u8 {
+            let nside = 1_u64 << depth as u32;
+            let npix = 12 * nside * nside;
+            for ipix_ring in 0..npix {
+                let _ = ring2xyf(nside, ipix_ring);
+            }
+        }
+    }
+
+    #[test]
+    fn ring_above_south_cap_matches_north_cap_by_symmetry() {
+        // z <= -2/3 used to be bracketed one ring too close to the pole
+        // because the south-cap branch was missing a -1 term. The ring
+        // count below a given |z| in the south cap must mirror the north.
+        for depth in 1..=5u8 {
+            let nside = 1_u64 << depth as u32;
+            for i in 1..nside {
+                let z = 1.0 - (i * i) as f64 / (3 * nside * nside) as f64;
+                let north = ring_above(nside, z);
+                let south = ring_above(nside, -z);
+                assert_eq!(
+                    south, 4 * nside - 1 - north,
+                    "nside={} z={} north={} south={}", nside, z, north, south
+                );
+            }
+        }
+    }
+}